@@ -0,0 +1,72 @@
+// Copyright 2019 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mouse cursor shapes, backed by the X11 cursor font.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::os::raw::c_uint;
+
+use x11_dl::xlib;
+
+use crate::x11::win_main::XLIB;
+
+/// A platform-independent mouse cursor shape.
+///
+/// Shapes the X11 cursor font has no glyph for fall back to `Arrow`,
+/// mirroring how other backends degrade gracefully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MouseCursor {
+    Arrow,
+    IBeam,
+    Crosshair,
+    OpenHand,
+    Pointer,
+    ResizeLeftRight,
+    ResizeUpDown,
+    NotAllowed,
+}
+
+impl MouseCursor {
+    /// The `XC_*` cursor-font glyph this shape maps to. Every variant
+    /// currently has a native glyph.
+    fn glyph(self) -> c_uint {
+        match self {
+            MouseCursor::Arrow => xlib::XC_left_ptr,
+            MouseCursor::IBeam => xlib::XC_xterm,
+            MouseCursor::Crosshair => xlib::XC_crosshair,
+            MouseCursor::OpenHand => xlib::XC_hand1,
+            MouseCursor::Pointer => xlib::XC_hand2,
+            MouseCursor::ResizeLeftRight => xlib::XC_sb_h_double_arrow,
+            MouseCursor::ResizeUpDown => xlib::XC_sb_v_double_arrow,
+            MouseCursor::NotAllowed => xlib::XC_X_cursor,
+        }
+    }
+}
+
+thread_local! {
+    /// X11 cursors created by `XCreateFontCursor`, cached so repeated
+    /// `set_cursor` calls for the same shape don't leak a server resource.
+    static CURSOR_CACHE: RefCell<HashMap<MouseCursor, xlib::Cursor>> = RefCell::new(HashMap::new());
+}
+
+/// Look up (or create and cache) the X11 cursor for `cursor`.
+pub(crate) fn cursor_for(display: *mut xlib::Display, cursor: MouseCursor) -> xlib::Cursor {
+    CURSOR_CACHE.with(|cache| {
+        *cache
+            .borrow_mut()
+            .entry(cursor)
+            .or_insert_with(|| unsafe { (XLIB.XCreateFontCursor)(display, cursor.glyph()) })
+    })
+}
@@ -15,11 +15,15 @@
 //! x11 implementation of window creation.
 
 pub mod application;
+pub mod cursor;
 pub mod dialog;
 pub mod menu;
+pub mod screen;
 pub mod util;
 pub mod win_main;
+pub mod xinput2;
 
+pub use cursor::MouseCursor;
 pub use menu::Menu;
 
 use std::any::Any;
@@ -29,15 +33,21 @@ use std::ffi::OsString;
 use std::mem;
 use std::os::raw::*;
 use std::ptr;
+
+use raw_window_handle::{
+    HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle, XlibDisplayHandle,
+    XlibWindowHandle,
+};
 use x11_dl::xlib;
 use x11_dl::xlib::Display;
 
 use crate::keyboard::{KeyEvent, KeyModifiers};
+use crate::kurbo::{Point, Rect};
 use crate::platform::dialog::{FileDialogOptions, FileDialogType};
 use crate::window::{MouseButton, MouseEvent, WinHandler};
 use crate::Error;
 
-use crate::x11::win_main::{XLIB, XSESSION};
+use crate::x11::win_main::{add_idle_callback, register_window, WM_DELETE_WINDOW, XLIB, XSESSION};
 
 #[derive(Clone, Default)]
 pub struct WindowHandle {
@@ -57,35 +67,128 @@ impl WindowHandle {
     }
 
     pub fn close(&self) {
-        unimplemented!()
+        unsafe {
+            if let Some(display) = self.display {
+                let wm_protocols =
+                    (XLIB.XInternAtom)(display, CString::new("WM_PROTOCOLS").unwrap().as_ptr(), xlib::False);
+
+                let mut client_message: xlib::XClientMessageEvent = mem::uninitialized();
+                client_message.type_ = xlib::ClientMessage;
+                client_message.window = self.window;
+                client_message.message_type = wm_protocols;
+                client_message.format = 32;
+                client_message.data.set_long(0, *WM_DELETE_WINDOW as c_long);
+
+                let mut event = xlib::XEvent { client_message };
+                (XLIB.XSendEvent)(display, self.window, xlib::False, xlib::NoEventMask, &mut event);
+            }
+        }
+    }
+
+    pub fn set_cursor(&self, cursor: MouseCursor) {
+        unsafe {
+            if let Some(display) = self.display {
+                let x_cursor = cursor::cursor_for(display, cursor);
+                (XLIB.XDefineCursor)(display, self.window, x_cursor);
+            }
+        }
     }
 
     pub fn invalidate(&self) {
-        unimplemented!()
+        unsafe {
+            if let Some(display) = self.display {
+                // Send ourselves a synthetic Expose rather than
+                // `XClearArea`, which would immediately clear the window to
+                // its background pixel and flash it for a frame before the
+                // run loop's buffered repaint got a chance to blit over it.
+                let mut attributes: xlib::XWindowAttributes = mem::zeroed();
+                (XLIB.XGetWindowAttributes)(display, self.window, &mut attributes);
+
+                let mut expose: xlib::XExposeEvent = mem::zeroed();
+                expose.type_ = xlib::Expose;
+                expose.window = self.window;
+                expose.width = attributes.width;
+                expose.height = attributes.height;
+
+                let mut event = xlib::XEvent { expose };
+                (XLIB.XSendEvent)(display, self.window, xlib::False, xlib::ExposureMask, &mut event);
+            }
+        }
+    }
+
+    /// A handle whose drawing operations target `drawable` instead of the
+    /// real window. Used to hand `WinHandler::paint` the off-screen paint
+    /// buffer, so a repaint never touches the screen directly — only the
+    /// coalesced `XCopyArea` blit in the run loop does.
+    pub(crate) fn with_paint_target(&self, drawable: xlib::Drawable) -> WindowHandle {
+        WindowHandle {
+            window: drawable,
+            ..self.clone()
+        }
     }
 
     pub fn get_idle_handle(&self) -> Option<IdleHandle> {
-        unimplemented!()
+        Some(IdleHandle {
+            window: self.window,
+        })
     }
 
     pub fn get_dpi(&self) -> f32 {
-        unimplemented!()
+        screen::dpi_for_screen(self.screen)
+    }
+
+    /// The rectangles of every connected monitor, in the root window's
+    /// pixel coordinate space.
+    pub fn get_monitors(&self) -> Vec<Rect> {
+        screen::get_monitor_rects()
+    }
+
+    /// The monitor that this window currently sits on.
+    pub fn current_monitor(&self) -> Rect {
+        unsafe {
+            match self.display {
+                Some(display) => {
+                    // `XGetWindowAttributes`' x/y are relative to the window's
+                    // immediate parent, which under a reparenting window
+                    // manager is its decoration frame, not the root. Translate
+                    // the window's origin into root-relative coordinates
+                    // instead so multi-monitor lookups are actually correct.
+                    let mut root_x: c_int = 0;
+                    let mut root_y: c_int = 0;
+                    let mut child: xlib::Window = 0;
+                    (XLIB.XTranslateCoordinates)(
+                        display,
+                        self.window,
+                        self.root,
+                        0,
+                        0,
+                        &mut root_x,
+                        &mut root_y,
+                        &mut child,
+                    );
+                    screen::monitor_for_point(Point::new(root_x as f64, root_y as f64))
+                }
+                None => screen::get_monitor_rects()[0],
+            }
+        }
     }
 
     pub fn px_to_pixels(&self, x: f32) -> i32 {
-        unimplemented!()
+        (x * self.get_dpi() / 96.0) as i32
     }
 
     pub fn px_to_pixels_xy(&self, x: f32, y: f32) -> (i32, i32) {
-        unimplemented!()
+        let scale = self.get_dpi() / 96.0;
+        ((x * scale) as i32, (y * scale) as i32)
     }
 
     pub fn pixels_to_px<T: Into<f64>>(&self, x: T) -> f32 {
-        unimplemented!()
+        x.into() as f32 * 96.0 / self.get_dpi()
     }
 
     pub fn pixels_to_px_xy<T: Into<f64>>(&self, x: T, y: T) -> (f32, f32) {
-        unimplemented!()
+        let scale = 96.0 / self.get_dpi();
+        (x.into() as f32 * scale, y.into() as f32 * scale)
     }
 
     pub fn file_dialog(
@@ -97,6 +200,32 @@ impl WindowHandle {
     }
 }
 
+unsafe impl HasRawWindowHandle for WindowHandle {
+    fn raw_window_handle(&self) -> RawWindowHandle {
+        let mut handle = XlibWindowHandle::empty();
+        handle.window = self.window;
+        if let Some(display) = self.display {
+            unsafe {
+                let visual = (XLIB.XDefaultVisual)(display, self.screen);
+                handle.visual_id = (XLIB.XVisualIDFromVisual)(visual);
+            }
+        }
+        RawWindowHandle::Xlib(handle)
+    }
+}
+
+unsafe impl HasRawDisplayHandle for WindowHandle {
+    fn raw_display_handle(&self) -> RawDisplayHandle {
+        let mut handle = XlibDisplayHandle::empty();
+        handle.display = self
+            .display
+            .map(|display| display as *mut c_void)
+            .unwrap_or(ptr::null_mut());
+        handle.screen = self.screen;
+        RawDisplayHandle::Xlib(handle)
+    }
+}
+
 pub struct WindowBuilder {
     handler: Option<Box<dyn WinHandler>>,
     title: String,
@@ -172,17 +301,40 @@ impl WindowBuilder {
                 protocols.len() as c_int,
             );
 
-            Ok(WindowHandle {
+            let input_mask = xlib::ExposureMask
+                | xlib::KeyPressMask
+                | xlib::KeyReleaseMask
+                | xlib::ButtonPressMask
+                | xlib::ButtonReleaseMask
+                | xlib::PointerMotionMask
+                | xlib::StructureNotifyMask;
+            (XLIB.XSelectInput)(XSESSION.display, window, input_mask);
+
+            // When XI2 is available, also select its high-resolution motion
+            // and scroll events; the run loop prefers those over the core
+            // `MotionNotify`/button 4-5 events selected above.
+            xinput2::select_events(window);
+
+            let handle = WindowHandle {
                 display: Some(XSESSION.display),
                 screen,
                 root,
                 window,
-            })
+            };
+
+            let handler = self
+                .handler
+                .expect("WindowBuilder::build called without a handler");
+            register_window(window, handle.clone(), handler, 400, 300);
+
+            Ok(handle)
         }
     }
 }
 
-pub struct IdleHandle;
+pub struct IdleHandle {
+    window: xlib::Window,
+}
 
 impl IdleHandle {
     /// Add an idle handler, which is called (once) when the message loop
@@ -195,6 +347,6 @@ impl IdleHandle {
     where
         F: FnOnce(&dyn Any) + Send + 'static,
     {
-        unimplemented!()
+        add_idle_callback(self.window, Box::new(callback));
     }
 }
@@ -0,0 +1,97 @@
+// Copyright 2019 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multi-monitor geometry, queried via Xinerama when available.
+
+use lazy_static;
+use std::os::raw::c_int;
+use std::slice;
+
+use x11_dl::xinerama;
+
+use crate::kurbo::{Point, Rect, Size};
+
+use crate::x11::win_main::{XLIB, XSESSION};
+
+lazy_static! {
+    static ref XINERAMA: Option<xinerama::Xlib> = xinerama::Xlib::open().ok();
+}
+
+/// Enumerate the connected monitors as pixel rectangles in the root
+/// window's coordinate space.
+///
+/// When Xinerama is active this returns one rectangle per physical output;
+/// otherwise it falls back to a single rectangle covering the X11 default
+/// screen.
+pub fn get_monitor_rects() -> Vec<Rect> {
+    unsafe {
+        if let Some(xinerama) = XINERAMA.as_ref() {
+            if (xinerama.XineramaIsActive)(XSESSION.display) != 0 {
+                let mut count: c_int = 0;
+                let infos = (xinerama.XineramaQueryScreens)(XSESSION.display, &mut count);
+                if !infos.is_null() {
+                    let rects = slice::from_raw_parts(infos, count as usize)
+                        .iter()
+                        .map(|info| {
+                            Rect::from_origin_size(
+                                (info.x_org as f64, info.y_org as f64),
+                                Size::new(info.width as f64, info.height as f64),
+                            )
+                        })
+                        .collect();
+                    (XLIB.XFree)(infos as *mut _);
+                    return rects;
+                }
+            }
+        }
+        vec![default_screen_rect()]
+    }
+}
+
+/// The single-screen fallback used when Xinerama is absent or inactive,
+/// built from the default screen's reported width and height.
+fn default_screen_rect() -> Rect {
+    unsafe {
+        let screen_num = (XLIB.XDefaultScreen)(XSESSION.display);
+        let screen = (XLIB.XScreenOfDisplay)(XSESSION.display, screen_num);
+        let width = (XLIB.XWidthOfScreen)(screen);
+        let height = (XLIB.XHeightOfScreen)(screen);
+        Rect::from_origin_size((0.0, 0.0), Size::new(width as f64, height as f64))
+    }
+}
+
+/// The monitor rectangle that `origin` currently falls in, falling back to
+/// the first known monitor if it lies outside all of them.
+pub fn monitor_for_point(origin: Point) -> Rect {
+    let monitors = get_monitor_rects();
+    monitors
+        .iter()
+        .find(|rect| rect.contains(origin))
+        .cloned()
+        .unwrap_or(monitors[0])
+}
+
+/// Dots-per-inch of `screen_num`, derived from its reported pixel and
+/// physical (millimeter) dimensions. Falls back to 96 (the "no HiDPI")
+/// baseline when the display doesn't report physical size.
+pub fn dpi_for_screen(screen_num: c_int) -> f32 {
+    unsafe {
+        let width_px = (XLIB.XDisplayWidth)(XSESSION.display, screen_num);
+        let width_mm = (XLIB.XDisplayWidthMM)(XSESSION.display, screen_num);
+        if width_mm == 0 {
+            return 96.0;
+        }
+        (width_px as f32) * 25.4 / (width_mm as f32)
+    }
+}
@@ -15,10 +15,27 @@
 //! x11 implementation of runloop.
 
 use lazy_static;
+use libc;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
 use std::mem;
+use std::os::raw::{c_int, c_uint, c_void};
 use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use x11_dl::xinput2;
 use x11_dl::xlib;
 
+use crate::keyboard::{KeyEvent, KeyModifiers};
+use crate::kurbo::{Rect, Size};
+use crate::window::{MouseButton, MouseEvent, ScrollEvent, WinHandler};
+use crate::x11::xinput2 as xi2;
+use crate::x11::WindowHandle;
+
 lazy_static! {
     pub static ref XLIB: x11_dl::xlib::Xlib = xlib::Xlib::open().expect("Could not load xlib");
 }
@@ -51,6 +68,329 @@ lazy_static! {
     pub static ref XSESSION: XSession = XSession::new();
 }
 
+lazy_static! {
+    /// The `WM_DELETE_WINDOW` atom, interned once against the shared display
+    /// connection so the run loop can recognise a window-close request.
+    pub static ref WM_DELETE_WINDOW: xlib::Atom = unsafe {
+        let name = CString::new("WM_DELETE_WINDOW").unwrap();
+        (XLIB.XInternAtom)(XSESSION.display, name.as_ptr(), xlib::False)
+    };
+}
+
+/// The off-screen buffer a window paints into, blitted onto the window on
+/// each coalesced expose. Kept the size of the window and recreated on
+/// `ConfigureNotify` so resizing never blits stale, mismatched content.
+struct PaintBuffer {
+    pixmap: xlib::Pixmap,
+    gc: xlib::GC,
+    width: c_uint,
+    height: c_uint,
+}
+
+impl PaintBuffer {
+    fn new(display: *mut xlib::Display, window: xlib::Window, width: c_uint, height: c_uint) -> Self {
+        unsafe {
+            let screen = (XLIB.XDefaultScreen)(display);
+            let depth = (XLIB.XDefaultDepth)(display, screen) as c_uint;
+            let pixmap = (XLIB.XCreatePixmap)(display, window, width, height, depth);
+            let gc = (XLIB.XCreateGC)(display, pixmap, 0, ptr::null_mut());
+            PaintBuffer {
+                pixmap,
+                gc,
+                width,
+                height,
+            }
+        }
+    }
+}
+
+impl Drop for PaintBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            (XLIB.XFreeGC)(XSESSION.display, self.gc);
+            (XLIB.XFreePixmap)(XSESSION.display, self.pixmap);
+        }
+    }
+}
+
+/// A window, along with the handler it was built with, registered by its
+/// X window id so the run loop can route events to the right `WinHandler`.
+struct WindowState {
+    handle: WindowHandle,
+    handler: Box<dyn WinHandler>,
+    paint_buffer: PaintBuffer,
+    /// The union of the dirty rects from every `Expose` event seen since
+    /// the last repaint, so a batch of exposures only triggers one paint.
+    dirty: Option<Rect>,
+}
+
+thread_local! {
+    static WINDOWS: RefCell<HashMap<xlib::Window, Rc<RefCell<WindowState>>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Register a newly built window (and its handler) so the run loop can
+/// dispatch events to it. Called from `WindowBuilder::build`.
+pub(crate) fn register_window(
+    window: xlib::Window,
+    handle: WindowHandle,
+    handler: Box<dyn WinHandler>,
+    width: c_uint,
+    height: c_uint,
+) {
+    let paint_buffer = PaintBuffer::new(XSESSION.display, window, width, height);
+    WINDOWS.with(|windows| {
+        windows.borrow_mut().insert(
+            window,
+            Rc::new(RefCell::new(WindowState {
+                handle,
+                handler,
+                paint_buffer,
+                dirty: None,
+            })),
+        );
+    });
+}
+
+pub(crate) fn unregister_window(window: xlib::Window) {
+    WINDOWS.with(|windows| {
+        windows.borrow_mut().remove(&window);
+    });
+}
+
+fn lookup_window(window: xlib::Window) -> Option<Rc<RefCell<WindowState>>> {
+    WINDOWS.with(|windows| windows.borrow().get(&window).cloned())
+}
+
+/// Whether any window is still registered. The run loop uses this to tell
+/// "the last window closed" apart from "one of several windows closed".
+fn any_windows_open() -> bool {
+    WINDOWS.with(|windows| !windows.borrow().is_empty())
+}
+
+/// The self-pipe whose read end sits alongside the X connection fd in the
+/// run loop's `poll`, so a background thread can wake the main thread
+/// without the loop busy-polling.
+struct WakePipe {
+    read_fd: c_int,
+    write_fd: c_int,
+}
+
+impl WakePipe {
+    fn new() -> Self {
+        let mut fds = [0 as c_int; 2];
+        unsafe {
+            libc::pipe(fds.as_mut_ptr());
+            // The read end must be non-blocking: `drain` reads until the
+            // pipe is empty, and a blocking `read` on an empty pipe would
+            // hang the run loop forever instead of returning.
+            let flags = libc::fcntl(fds[0], libc::F_GETFL, 0);
+            libc::fcntl(fds[0], libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        WakePipe {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        }
+    }
+
+    fn wake(&self) {
+        unsafe {
+            libc::write(self.write_fd, [0u8].as_ptr() as *const c_void, 1);
+        }
+    }
+
+    /// Drain every byte written to the pipe since the last wakeup.
+    fn drain(&self) {
+        let mut buf = [0u8; 64];
+        unsafe {
+            loop {
+                let n = libc::read(self.read_fd, buf.as_mut_ptr() as *mut c_void, buf.len());
+                if n > 0 {
+                    continue;
+                }
+                // n == 0: the write end was closed. n < 0: EAGAIN/EWOULDBLOCK on
+                // the non-blocking fd once it's empty. Either way, we're done.
+                break;
+            }
+        }
+    }
+}
+
+unsafe impl Send for WakePipe {}
+unsafe impl Sync for WakePipe {}
+
+lazy_static! {
+    static ref WAKE_PIPE: WakePipe = WakePipe::new();
+}
+
+type IdleCallback = Box<dyn FnOnce(&dyn Any) + Send + 'static>;
+
+lazy_static! {
+    static ref IDLE_QUEUE: Mutex<VecDeque<(xlib::Window, IdleCallback)>> =
+        Mutex::new(VecDeque::new());
+}
+
+static QUIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Queue `callback` to run on the main thread the next time the run loop
+/// wakes, then wake it. Called by `IdleHandle::add_idle`.
+pub(crate) fn add_idle_callback(window: xlib::Window, callback: IdleCallback) {
+    IDLE_QUEUE.lock().unwrap().push_back((window, callback));
+    WAKE_PIPE.wake();
+}
+
+/// Ask the run loop to exit. Called by `win_main::request_quit`.
+pub(crate) fn set_quit_requested() {
+    QUIT_REQUESTED.store(true, Ordering::SeqCst);
+    WAKE_PIPE.wake();
+}
+
+/// Run every queued idle callback against the `WinHandler` of the window it
+/// was scheduled for, silently dropping callbacks whose window has since
+/// been destroyed.
+fn drain_idle_queue() {
+    let callbacks: Vec<_> = IDLE_QUEUE.lock().unwrap().drain(..).collect();
+    for (window, callback) in callbacks {
+        if let Some(state) = lookup_window(window) {
+            let mut state = state.borrow_mut();
+            callback(state.handler.as_any());
+        }
+    }
+}
+
+fn mouse_button(button: c_uint) -> MouseButton {
+    match button {
+        1 => MouseButton::Left,
+        2 => MouseButton::Middle,
+        3 => MouseButton::Right,
+        _ => MouseButton::Left,
+    }
+}
+
+fn is_scroll_button(button: c_uint) -> bool {
+    button == 4 || button == 5
+}
+
+fn mouse_event_from_button(event: &xlib::XButtonEvent) -> MouseEvent {
+    MouseEvent {
+        x: event.x as i32,
+        y: event.y as i32,
+        mods: KeyModifiers::default(),
+        button: mouse_button(event.button as c_uint),
+        count: 1,
+    }
+}
+
+fn mouse_event_from_motion(event: &xlib::XMotionEvent) -> MouseEvent {
+    MouseEvent {
+        x: event.x as i32,
+        y: event.y as i32,
+        mods: KeyModifiers::default(),
+        button: MouseButton::Left,
+        count: 0,
+    }
+}
+
+/// Pixels scrolled per legacy button 4/5 click, approximating one text
+/// line. Only used as a fallback when XI2 can't report a smooth delta.
+const LEGACY_SCROLL_LINE_HEIGHT: f64 = 20.0;
+
+fn scroll_event_from_legacy_button(event: &xlib::XButtonEvent) -> ScrollEvent {
+    let dy = if event.button as c_uint == 4 {
+        -LEGACY_SCROLL_LINE_HEIGHT
+    } else {
+        LEGACY_SCROLL_LINE_HEIGHT
+    };
+    ScrollEvent {
+        dx: 0.0,
+        dy,
+        mods: KeyModifiers::default(),
+    }
+}
+
+/// Fetch, decode, and dispatch a generic (XI2) event, if it's one we care
+/// about and the extension is actually active on this display.
+fn handle_xi2_event(event: &mut xlib::XEvent) {
+    let opcode = match xi2::opcode() {
+        Some(opcode) => opcode,
+        None => return,
+    };
+    unsafe {
+        let cookie = &mut event.generic_event_cookie;
+        if cookie.extension != opcode {
+            return;
+        }
+        if (XLIB.XGetEventData)(XSESSION.display, cookie) == 0 {
+            return;
+        }
+        if cookie.evtype == xinput2::XI_Motion {
+            let xi_event = &*(cookie.data as *const xinput2::XIDeviceEvent);
+            match xi2::decode_motion(xi_event) {
+                xi2::Xi2Event::Scroll { window, dx, dy } => {
+                    if let Some(state) = lookup_window(window) {
+                        let mut state = state.borrow_mut();
+                        let scroll_event = ScrollEvent {
+                            dx,
+                            dy,
+                            mods: KeyModifiers::default(),
+                        };
+                        state.handler.scroll(&scroll_event);
+                    }
+                }
+                xi2::Xi2Event::Motion { window, x, y } => {
+                    if let Some(state) = lookup_window(window) {
+                        let mut state = state.borrow_mut();
+                        let mouse_event = MouseEvent {
+                            x: x as i32,
+                            y: y as i32,
+                            mods: KeyModifiers::default(),
+                            button: MouseButton::Left,
+                            count: 0,
+                        };
+                        state.handler.mouse_move(&mouse_event);
+                    }
+                }
+                _ => unreachable!("XI_Motion never decodes to ButtonPress"),
+            }
+        } else if cookie.evtype == xinput2::XI_ButtonPress {
+            // `select_events` asked for this once XI2 was active, so the
+            // legacy core `ButtonPress` (gated behind `xi2::opcode().is_none()`
+            // below) no longer arrives for it; dispatch from here instead.
+            let xi_event = &*(cookie.data as *const xinput2::XIDeviceEvent);
+            if let xi2::Xi2Event::ButtonPress { window, x, y, button } = xi2::decode_button_press(xi_event) {
+                if !is_scroll_button(button) {
+                    if let Some(state) = lookup_window(window) {
+                        let mut state = state.borrow_mut();
+                        let mouse_event = MouseEvent {
+                            x: x as i32,
+                            y: y as i32,
+                            mods: KeyModifiers::default(),
+                            button: mouse_button(button),
+                            count: 1,
+                        };
+                        state.handler.mouse_down(&mouse_event);
+                    }
+                }
+            }
+        }
+        (XLIB.XFreeEventData)(XSESSION.display, cookie);
+    }
+}
+
+fn key_event_from_xkey(event: &xlib::XKeyEvent) -> KeyEvent {
+    unsafe {
+        let mut keysym: xlib::KeySym = 0;
+        (XLIB.XLookupString)(
+            event as *const xlib::XKeyEvent as *mut xlib::XKeyEvent,
+            ptr::null_mut(),
+            0,
+            &mut keysym,
+            ptr::null_mut(),
+        );
+        KeyEvent::from_keysym(keysym as u32, KeyModifiers::default())
+    }
+}
+
 pub struct RunLoop;
 
 impl RunLoop {
@@ -60,14 +400,178 @@ impl RunLoop {
 
     pub fn run(&mut self) {
         unsafe {
+            let x11_fd = (XLIB.XConnectionNumber)(XSESSION.display);
+            let mut poll_fds = [
+                libc::pollfd {
+                    fd: x11_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: WAKE_PIPE.read_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
             let mut event: xlib::XEvent = mem::uninitialized();
-            loop {
-                (XLIB.XNextEvent)(XSESSION.display, &mut event);
+            'run_loop: loop {
+                // Block until either the X connection or the wake pipe has
+                // something to read, rather than spinning on XNextEvent.
+                poll_fds[0].revents = 0;
+                poll_fds[1].revents = 0;
+                libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1);
+
+                if poll_fds[1].revents & libc::POLLIN != 0 {
+                    WAKE_PIPE.drain();
+                    if QUIT_REQUESTED.load(Ordering::SeqCst) {
+                        break 'run_loop;
+                    }
+                    drain_idle_queue();
+                }
+
+                while (XLIB.XPending)(XSESSION.display) > 0 {
+                    (XLIB.XNextEvent)(XSESSION.display, &mut event);
+                    match event.get_type() {
+                        xlib::Expose => {
+                            let expose = event.expose;
+                            if let Some(state_rc) = lookup_window(expose.window) {
+                                let rect = Rect::from_origin_size(
+                                    (expose.x as f64, expose.y as f64),
+                                    Size::new(expose.width as f64, expose.height as f64),
+                                );
+                                {
+                                    let mut state = state_rc.borrow_mut();
+                                    state.dirty = Some(match state.dirty {
+                                        Some(dirty) => dirty.union(rect),
+                                        None => rect,
+                                    });
+                                }
+                                // `count` is the number of further Expose events still
+                                // queued for this window; only repaint once the batch
+                                // has fully drained.
+                                if expose.count == 0 {
+                                    let mut state = state_rc.borrow_mut();
+                                    let dirty = state.dirty.take().expect("dirty rect was just set");
+                                    let pixmap = state.paint_buffer.pixmap;
+                                    let gc = state.paint_buffer.gc;
+                                    // Paint into the off-screen pixmap, not the
+                                    // real window, so the blit below is the only
+                                    // thing that ever touches the screen.
+                                    let paint_handle = state.handle.with_paint_target(pixmap);
+                                    state.handler.paint(&paint_handle, &dirty);
+                                    (XLIB.XCopyArea)(
+                                        XSESSION.display,
+                                        pixmap,
+                                        expose.window,
+                                        gc,
+                                        dirty.x0 as c_int,
+                                        dirty.y0 as c_int,
+                                        dirty.width() as c_uint,
+                                        dirty.height() as c_uint,
+                                        dirty.x0 as c_int,
+                                        dirty.y0 as c_int,
+                                    );
+                                }
+                            }
+                        }
+                        xlib::ConfigureNotify => {
+                            let configure = event.configure;
+                            if let Some(state_rc) = lookup_window(configure.window) {
+                                let mut state = state_rc.borrow_mut();
+                                let width = configure.width as c_uint;
+                                let height = configure.height as c_uint;
+                                if width != state.paint_buffer.width || height != state.paint_buffer.height
+                                {
+                                    state.paint_buffer =
+                                        PaintBuffer::new(XSESSION.display, configure.window, width, height);
+                                }
+                            }
+                        }
+                        xlib::ButtonPress => {
+                            // When XI2 is active, button presses arrive as a
+                            // GenericEvent (XI_ButtonPress) instead: once a
+                            // client selects an XI2 counterpart for an event
+                            // class, the server stops delivering the legacy
+                            // core version of it to that client.
+                            if xi2::opcode().is_none() {
+                                let button = event.button;
+                                if let Some(state) = lookup_window(button.window) {
+                                    let mut state = state.borrow_mut();
+                                    if is_scroll_button(button.button as c_uint) {
+                                        let scroll_event = scroll_event_from_legacy_button(&button);
+                                        state.handler.scroll(&scroll_event);
+                                    } else {
+                                        let mouse_event = mouse_event_from_button(&button);
+                                        state.handler.mouse_down(&mouse_event);
+                                    }
+                                }
+                            }
+                        }
+                        xlib::GenericEvent => {
+                            handle_xi2_event(&mut event);
+                        }
+                        xlib::ButtonRelease => {
+                            let button = event.button;
+                            if !is_scroll_button(button.button as c_uint) {
+                                if let Some(state) = lookup_window(button.window) {
+                                    let mut state = state.borrow_mut();
+                                    let mouse_event = mouse_event_from_button(&button);
+                                    state.handler.mouse_up(&mouse_event);
+                                }
+                            }
+                        }
+                        xlib::MotionNotify => {
+                            // When XI2 is active, higher-resolution motion
+                            // arrives as a GenericEvent instead.
+                            if xi2::opcode().is_none() {
+                                let motion = event.motion;
+                                if let Some(state) = lookup_window(motion.window) {
+                                    let mut state = state.borrow_mut();
+                                    let mouse_event = mouse_event_from_motion(&motion);
+                                    state.handler.mouse_move(&mouse_event);
+                                }
+                            }
+                        }
+                        xlib::KeyPress => {
+                            let key = event.key;
+                            if let Some(state) = lookup_window(key.window) {
+                                let mut state = state.borrow_mut();
+                                let key_event = key_event_from_xkey(&key);
+                                state.handler.key_down(key_event);
+                            }
+                        }
+                        xlib::KeyRelease => {
+                            let key = event.key;
+                            if let Some(state) = lookup_window(key.window) {
+                                let mut state = state.borrow_mut();
+                                let key_event = key_event_from_xkey(&key);
+                                state.handler.key_up(key_event);
+                            }
+                        }
+                        xlib::ClientMessage => {
+                            let client_message = event.client_message;
+                            if client_message.data.get_long(0) as xlib::Atom == *WM_DELETE_WINDOW {
+                                if let Some(state) = lookup_window(client_message.window) {
+                                    state.borrow_mut().handler.destroy();
+                                }
+                                unregister_window(client_message.window);
+                                // Closing one window shouldn't kill a multi-window
+                                // app; only stop the loop once every registered
+                                // window has been closed.
+                                if !any_windows_open() {
+                                    break 'run_loop;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
             }
         }
     }
 }
 
 pub fn request_quit() {
-    unimplemented!()
+    set_quit_requested();
 }
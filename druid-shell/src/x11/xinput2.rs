@@ -0,0 +1,255 @@
+// Copyright 2019 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! High resolution scrolling and pointer motion via the XInput2 extension.
+//!
+//! Legacy X11 only reports scrolling as clicks of button 4/5, which is
+//! coarse and has no horizontal axis. When XI2 is present we instead read
+//! the raw valuator axes of `XI_Motion` events, which report absolute,
+//! fractional accumulator positions per axis; subtracting the previous
+//! reading for the same (device, valuator) pair gives a smooth delta.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::os::raw::{c_int, c_uint};
+use std::slice;
+
+use lazy_static;
+use x11_dl::xinput2;
+use x11_dl::xlib;
+
+use crate::x11::win_main::{XLIB, XSESSION};
+
+lazy_static! {
+    static ref XINPUT2: Option<xinput2::XInput2> = xinput2::XInput2::open().ok();
+}
+
+lazy_static! {
+    /// The XInput2 extension's major opcode, queried once against the
+    /// shared display connection. `None` means XI2 is unavailable (or too
+    /// old to support the axes we need), so callers fall back to legacy
+    /// button 4/5 scrolling and core `MotionNotify` events.
+    static ref XI2_OPCODE: Option<c_int> = unsafe { query_opcode(XSESSION.display) };
+}
+
+unsafe fn query_opcode(display: *mut xlib::Display) -> Option<c_int> {
+    let xinput2 = XINPUT2.as_ref()?;
+    let name = CString::new("XInputExtension").unwrap();
+    let mut opcode = 0;
+    let mut first_event = 0;
+    let mut first_error = 0;
+    if (XLIB.XQueryExtension)(display, name.as_ptr(), &mut opcode, &mut first_event, &mut first_error) == 0 {
+        return None;
+    }
+    let mut major = 2;
+    let mut minor = 0;
+    if (xinput2.XIQueryVersion)(display, &mut major, &mut minor) != xlib::Success as c_int {
+        return None;
+    }
+    Some(opcode)
+}
+
+/// `Some(opcode)` if XI2 is usable on this display; `None` if the caller
+/// should stick to legacy core events.
+pub(crate) fn opcode() -> Option<c_int> {
+    *XI2_OPCODE
+}
+
+/// Select `XI_Motion` and `XI_ButtonPress` on `window` for every master
+/// pointer device, so scroll and move events arrive as XI2 generic events.
+pub(crate) fn select_events(window: xlib::Window) {
+    let xinput2 = match XINPUT2.as_ref() {
+        Some(xinput2) => xinput2,
+        None => return,
+    };
+    unsafe {
+        let mut mask_bytes = [0u8; (xinput2::XI_LASTEVENT as usize / 8) + 1];
+        set_mask_bit(&mut mask_bytes, xinput2::XI_Motion);
+        set_mask_bit(&mut mask_bytes, xinput2::XI_ButtonPress);
+
+        let mut event_mask = xinput2::XIEventMask {
+            deviceid: xinput2::XIAllMasterDevices,
+            mask_len: mask_bytes.len() as c_int,
+            mask: mask_bytes.as_mut_ptr(),
+        };
+
+        (xinput2.XISelectEvents)(XSESSION.display, window, &mut event_mask, 1);
+    }
+}
+
+fn set_mask_bit(mask: &mut [u8], event_type: c_int) {
+    let event_type = event_type as usize;
+    mask[event_type / 8] |= 1 << (event_type % 8);
+}
+
+fn mask_bit_is_set(mask: &[u8], bit: c_int) -> bool {
+    let bit = bit as usize;
+    (mask[bit / 8] & (1 << (bit % 8))) != 0
+}
+
+thread_local! {
+    /// The last absolute valuator reading per (device id, valuator number).
+    /// XI2 reports a cumulative scroll position, not a delta, so a smooth
+    /// per-event increment has to be derived by diffing against this.
+    static VALUATOR_STATE: RefCell<HashMap<(c_int, c_int), f64>> = RefCell::new(HashMap::new());
+
+    /// Valuator axis labels, keyed by (device id, valuator number). Filled
+    /// in one `XIQueryDevice` round trip per device the first time any of
+    /// its axes are seen, so decoding a motion/scroll event never pays a
+    /// server round trip per axis per event.
+    static AXIS_LABELS: RefCell<HashMap<(c_int, c_int), String>> = RefCell::new(HashMap::new());
+
+    /// Devices whose axis labels have already been queried, so an axis
+    /// with no label doesn't trigger a fresh query on every event.
+    static QUERIED_DEVICES: RefCell<HashSet<c_int>> = RefCell::new(HashSet::new());
+}
+
+/// A pointer update decoded from an XI2 generic event.
+pub(crate) enum Xi2Event {
+    Scroll { window: xlib::Window, dx: f64, dy: f64 },
+    Motion { window: xlib::Window, x: f64, y: f64 },
+    ButtonPress { window: xlib::Window, x: f64, y: f64, button: c_uint },
+}
+
+/// Decode an already-fetched `XI_Motion` cookie, returning a smooth scroll
+/// delta when the event carries scroll-axis valuators, or a plain
+/// high-resolution motion otherwise.
+pub(crate) unsafe fn decode_motion(event: &xinput2::XIDeviceEvent) -> Xi2Event {
+    let (dx, dy, saw_scroll_axis) = scroll_delta(event);
+    if saw_scroll_axis {
+        // Even a zero delta (the first reading for this device/axis, which
+        // has no previous value to diff against) must still be reported as
+        // a scroll, not a move: otherwise the user's first scroll tick per
+        // device session is silently reinterpreted as a mouse move.
+        Xi2Event::Scroll {
+            window: event.event,
+            dx,
+            dy,
+        }
+    } else {
+        Xi2Event::Motion {
+            window: event.event,
+            x: event.event_x,
+            y: event.event_y,
+        }
+    }
+}
+
+/// Decode an already-fetched `XI_ButtonPress` cookie into a `mouse_down`.
+pub(crate) unsafe fn decode_button_press(event: &xinput2::XIDeviceEvent) -> Xi2Event {
+    Xi2Event::ButtonPress {
+        window: event.event,
+        x: event.event_x,
+        y: event.event_y,
+        button: event.detail as c_uint,
+    }
+}
+
+/// The scroll delta carried by `event`'s valuators, and whether any of them
+/// was a scroll axis at all (as opposed to a plain motion axis) — the latter
+/// is tracked separately from "delta happens to be zero" so a first reading
+/// of a scroll axis isn't misclassified as plain motion.
+unsafe fn scroll_delta(event: &xinput2::XIDeviceEvent) -> (f64, f64, bool) {
+    let mut dx = 0.0;
+    let mut dy = 0.0;
+    let mut saw_scroll_axis = false;
+    let mask = slice::from_raw_parts(event.valuators.mask as *const u8, event.valuators.mask_len as usize);
+    let values = slice::from_raw_parts(
+        event.valuators.values,
+        (0..mask.len() as c_int * 8)
+            .filter(|bit| mask_bit_is_set(mask, *bit))
+            .count(),
+    );
+
+    let mut value_index = 0;
+    for bit in 0..(mask.len() as c_int * 8) {
+        if !mask_bit_is_set(mask, bit) {
+            continue;
+        }
+        let value = values[value_index];
+        value_index += 1;
+
+        if let Some(label) = axis_label(event.deviceid, bit) {
+            let is_scroll_axis = label == "Rel Vert Scroll" || label == "Rel Horiz Scroll";
+            saw_scroll_axis |= is_scroll_axis;
+
+            let key = (event.deviceid, bit);
+            let previous = VALUATOR_STATE.with(|state| state.borrow().get(&key).copied());
+            VALUATOR_STATE.with(|state| state.borrow_mut().insert(key, value));
+            if let Some(previous) = previous {
+                let delta = value - previous;
+                match label.as_str() {
+                    "Rel Vert Scroll" => dy += delta,
+                    "Rel Horiz Scroll" => dx += delta,
+                    _ => {}
+                }
+            }
+        }
+    }
+    (dx, dy, saw_scroll_axis)
+}
+
+/// The label atom name of `device`'s valuator `number`, e.g.
+/// `"Rel Vert Scroll"`, served from `AXIS_LABELS` once `device` has been
+/// queried at all.
+fn axis_label(device: c_int, number: c_int) -> Option<String> {
+    let key = (device, number);
+    let cached = AXIS_LABELS.with(|cache| cache.borrow().get(&key).cloned());
+    if cached.is_some() {
+        return cached;
+    }
+    let first_query = QUERIED_DEVICES.with(|queried| queried.borrow_mut().insert(device));
+    if !first_query {
+        return None;
+    }
+    query_axis_labels(device);
+    AXIS_LABELS.with(|cache| cache.borrow().get(&key).cloned())
+}
+
+/// Fetch every valuator axis label `device` reports in a single
+/// `XIQueryDevice` round trip and cache them all, so later axes on the same
+/// device are served from `AXIS_LABELS` without another round trip.
+fn query_axis_labels(device: c_int) {
+    use std::ffi::CStr;
+
+    let xinput2 = match XINPUT2.as_ref() {
+        Some(xinput2) => xinput2,
+        None => return,
+    };
+    unsafe {
+        let mut found = 0;
+        let devices = (xinput2.XIQueryDevice)(XSESSION.display, device, &mut found);
+        if devices.is_null() {
+            return;
+        }
+        let info = &*devices;
+        let classes = slice::from_raw_parts(info.classes, info.num_classes as usize);
+        for class in classes {
+            let class = &**class;
+            if class._type == xinput2::XIValuatorClass {
+                let valuator = &*(class as *const xinput2::XIAnyClassInfo as *const xinput2::XIValuatorClassInfo);
+                let name_ptr = (XLIB.XGetAtomName)(XSESSION.display, valuator.label);
+                if !name_ptr.is_null() {
+                    let label = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
+                    (XLIB.XFree)(name_ptr as *mut _);
+                    AXIS_LABELS.with(|cache| {
+                        cache.borrow_mut().insert((device, valuator.number), label);
+                    });
+                }
+            }
+        }
+        (xinput2.XIFreeDeviceInfo)(devices);
+    }
+}